@@ -1,41 +1,30 @@
 //! An `env` is an abstraction layer that allows the database to run both on different platforms as
 //! well as persisting data on disk or in memory.
 
-use error::{self, Result};
+use error::Result;
 
-use std::io::{self, Read, Write, Seek};
+use std::io::{Read, Write};
 use std::path::Path;
-use std::sync::{Arc, Mutex};
 
-/// RandomAccessFile wraps a type implementing read and seek to enable atomic random reads
-#[derive(Clone)]
-pub struct RandomAccessFile<F: Read + Seek> {
-    f: Arc<Mutex<F>>,
-}
-
-impl<F: Read + Seek> RandomAccessFile<F> {
-    pub fn new(f: F) -> RandomAccessFile<F> {
-        RandomAccessFile { f: Arc::new(Mutex::new(f)) }
-    }
-
-    pub fn read_at(&self, off: usize, len: usize) -> Result<Vec<u8>> {
-        let mut f = try!(error::from_lock_result(self.f.lock()));
-        try!(error::from_io_result(f.seek(io::SeekFrom::Start(off as u64))));
-
-        let mut buf = Vec::new();
-        buf.resize(len, 0);
-        error::from_io_result(f.read_exact(&mut buf)).map(|_| buf)
-    }
+/// RandomAccess is implemented by types that serve reads at arbitrary offsets through `&self`
+/// into a caller-supplied buffer, so the trait itself imposes no lock and no per-call allocation.
+/// The disk implementation (`PosixRandomAccessFile`) backs this with positioned reads
+/// (`pread`/`seek_read`) so multiple table readers can share one open file handle; other
+/// implementations may still lock or allocate internally. `Send + Sync` is required so a `Box<RandomAccess>` can be wrapped in an `Arc` and
+/// handed to worker threads, which is the whole point of not locking it.
+pub trait RandomAccess: Send + Sync {
+    /// Reads up to `dst.len()` bytes starting at `off` into `dst`, returning the number of bytes
+    /// actually read.
+    fn read_at(&self, off: usize, dst: &mut [u8]) -> Result<usize>;
 }
 
 pub trait Env {
     type SequentialReader: Read;
-    type RandomReader: Read + Seek;
     type Writer: Write;
     type FileLock;
 
     fn open_sequential_file(&self, &Path) -> Result<Self::SequentialReader>;
-    fn open_random_access_file(&self, &Path) -> Result<RandomAccessFile<Self::RandomReader>>;
+    fn open_random_access_file(&self, &Path) -> Result<Box<RandomAccess>>;
     fn open_writable_file(&self, &Path) -> Result<Self::Writer>;
     fn open_appendable_file(&self, &Path) -> Result<Self::Writer>;
 
@@ -57,17 +46,135 @@ pub trait Env {
     fn sleep_for(&self, micros: u32);
 }
 
+/// Severity of a single log line, most to least severe.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl LogLevel {
+    fn name(&self) -> &'static str {
+        match *self {
+            LogLevel::Error => "ERROR",
+            LogLevel::Warn => "WARN",
+            LogLevel::Info => "INFO",
+            LogLevel::Debug => "DEBUG",
+        }
+    }
+}
+
+/// Logger writes timestamped, leveled diagnostic lines, filtering out anything less severe than
+/// its configured minimum level. The timestamp comes from the owning `Env::micros`, supplied at
+/// construction so `Logger` itself stays independent of any particular `Env` implementation.
 pub struct Logger {
     dst: Box<Write>,
+    min_level: LogLevel,
+    now: Box<Fn() -> u64>,
 }
 
 impl Logger {
-    pub fn new(w: Box<Write>) -> Logger {
-        Logger { dst: w }
+    /// Creates a logger that writes to `w`, using `now` to stamp each line, at the default
+    /// minimum level of `Info`.
+    pub fn new(w: Box<Write>, now: Box<Fn() -> u64>) -> Logger {
+        Logger { dst: w, min_level: LogLevel::Info, now: now }
     }
 
+    pub fn set_min_level(&mut self, level: LogLevel) {
+        self.min_level = level;
+    }
+
+    /// Writes `message` at `level`, prefixed with a microsecond timestamp and the level name,
+    /// unless `level` is less severe than the logger's configured minimum.
+    pub fn log_at(&mut self, level: LogLevel, message: &str) {
+        if level > self.min_level {
+            return;
+        }
+        let ts = (self.now)();
+        let _ = write!(self.dst, "{} {} {}\n", ts, level.name(), message);
+    }
+
+    /// Logs `message` at `Info` level; kept for compatibility with callers that don't care about
+    /// severity.
     pub fn log(&mut self, message: &String) {
-        let _ = self.dst.write(message.as_bytes());
-        let _ = self.dst.write("\n".as_bytes());
+        self.log_at(LogLevel::Info, message);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, data: &[u8]) -> ::std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(data);
+            Ok(data.len())
+        }
+
+        fn flush(&mut self) -> ::std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn logger_over(buf: &Arc<Mutex<Vec<u8>>>) -> Logger {
+        Logger::new(Box::new(SharedBuf(buf.clone())), Box::new(|| 42))
+    }
+
+    fn contents(buf: &Arc<Mutex<Vec<u8>>>) -> String {
+        String::from_utf8(buf.lock().unwrap().clone()).unwrap()
+    }
+
+    #[test]
+    fn test_default_min_level_suppresses_debug_but_not_info() {
+        let buf = Arc::new(Mutex::new(Vec::new()));
+        let mut logger = logger_over(&buf);
+
+        logger.log_at(LogLevel::Debug, "too chatty");
+        logger.log_at(LogLevel::Info, "normal operation");
+
+        let out = contents(&buf);
+        assert!(!out.contains("too chatty"));
+        assert!(out.contains("INFO normal operation"));
+        assert!(out.contains("42"));
+    }
+
+    #[test]
+    fn test_set_min_level_allows_debug_through() {
+        let buf = Arc::new(Mutex::new(Vec::new()));
+        let mut logger = logger_over(&buf);
+        logger.set_min_level(LogLevel::Debug);
+
+        logger.log_at(LogLevel::Debug, "now visible");
+
+        assert!(contents(&buf).contains("DEBUG now visible"));
+    }
+
+    #[test]
+    fn test_error_always_logged_even_at_strictest_level() {
+        let buf = Arc::new(Mutex::new(Vec::new()));
+        let mut logger = logger_over(&buf);
+        logger.set_min_level(LogLevel::Error);
+
+        logger.log_at(LogLevel::Warn, "suppressed");
+        logger.log_at(LogLevel::Error, "always shown");
+
+        let out = contents(&buf);
+        assert!(!out.contains("suppressed"));
+        assert!(out.contains("ERROR always shown"));
+    }
+
+    #[test]
+    fn test_log_shim_uses_info_level() {
+        let buf = Arc::new(Mutex::new(Vec::new()));
+        let mut logger = logger_over(&buf);
+
+        logger.log(&"via shim".to_string());
+
+        assert!(contents(&buf).contains("INFO via shim"));
     }
 }
@@ -0,0 +1,542 @@
+//! `EncryptedEnv` wraps an arbitrary `Env` and transparently encrypts file contents at rest,
+//! modeled on the protected-file approach used in the SGX port: files are split into plaintext
+//! blocks of at most `BLOCK_SIZE` bytes, each sealed with an AEAD under a nonce derived from a
+//! per-file salt and the block index. Blocks are not fixed-size: a `flush()` seals whatever is
+//! buffered as a shorter block, so readers locate blocks by walking their headers. This way SSTables, the WAL and MANIFEST stay confidential and tamper-evident on disk
+//! while the rest of the database keeps talking to a plain `Env`.
+
+use env::{Env, Logger, RandomAccess};
+use error::{self, Result};
+
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+
+/// Size of the master key, in bytes.
+pub const KEY_SIZE: usize = 32;
+/// Maximum number of plaintext bytes sealed into one block; `flush` and drop seal shorter ones.
+const BLOCK_SIZE: usize = 4096;
+/// Per-file random salt stored at the start of every encrypted file.
+const SALT_SIZE: usize = 16;
+/// Length prefix + auth tag stored ahead of each block's ciphertext.
+const BLOCK_HEADER_SIZE: usize = 4 + 16;
+
+fn derive_nonce(salt: &[u8; SALT_SIZE], block_idx: u64) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce.copy_from_slice(&salt[..12]);
+    for (i, b) in block_idx.to_le_bytes().iter().enumerate() {
+        nonce[i] ^= *b;
+    }
+    *Nonce::from_slice(&nonce)
+}
+
+fn crypto_err<T>(msg: &str) -> Result<T> {
+    error::from_io_result(Err(io::Error::new(io::ErrorKind::Other, msg.to_string())))
+}
+
+/// Walks the sealed-block headers of an encrypted file, starting right after the salt, without
+/// decrypting any ciphertext. Blocks are variable-length (a `flush()` mid-stream seals a short
+/// block instead of padding to `BLOCK_SIZE`), so both `size_of` and resuming an append need to
+/// walk block-by-block via each header's length rather than assume a fixed stride. Returns the
+/// total plaintext length and the number of blocks found.
+fn scan_blocks(raw: &RandomAccess) -> Result<(usize, u64)> {
+    let mut plain = 0;
+    let mut count = 0u64;
+    let mut off = SALT_SIZE;
+    loop {
+        let mut hdr = [0u8; BLOCK_HEADER_SIZE];
+        let n = try!(raw.read_at(off, &mut hdr));
+        if n < BLOCK_HEADER_SIZE {
+            break;
+        }
+        let len = le_u32(&hdr[..4]) as usize;
+        plain += len;
+        count += 1;
+        off += BLOCK_HEADER_SIZE + len;
+    }
+    Ok((plain, count))
+}
+
+/// `EncryptedEnv` encrypts file contents at rest while delegating directory/lock/clock operations
+/// straight through to the wrapped `Env`.
+pub struct EncryptedEnv<E: Env> {
+    inner: E,
+    key: [u8; KEY_SIZE],
+}
+
+impl<E: Env> EncryptedEnv<E> {
+    /// Wraps `inner` so that every file it creates is encrypted under `key`.
+    pub fn new(inner: E, key: [u8; KEY_SIZE]) -> EncryptedEnv<E> {
+        EncryptedEnv { inner: inner, key: key }
+    }
+
+    fn cipher(&self) -> ChaCha20Poly1305 {
+        ChaCha20Poly1305::new(Key::from_slice(&self.key))
+    }
+}
+
+impl<E: Env> Env for EncryptedEnv<E> {
+    type SequentialReader = EncryptedSequentialReader<E::SequentialReader>;
+    type Writer = EncryptedWriter<E::Writer>;
+    type FileLock = E::FileLock;
+
+    fn open_sequential_file(&self, path: &Path) -> Result<Self::SequentialReader> {
+        let mut r = try!(self.inner.open_sequential_file(path));
+        let mut salt = [0u8; SALT_SIZE];
+        try!(error::from_io_result(r.read_exact(&mut salt)));
+        Ok(EncryptedSequentialReader {
+            inner: r,
+            cipher: self.cipher(),
+            salt: salt,
+            block_idx: 0,
+            buf: Vec::new(),
+            pos: 0,
+        })
+    }
+
+    fn open_random_access_file(&self, path: &Path) -> Result<Box<RandomAccess>> {
+        let inner = try!(self.inner.open_random_access_file(path));
+        Ok(Box::new(EncryptedRandomAccess {
+            inner: inner,
+            cipher: self.cipher(),
+        }))
+    }
+
+    fn open_writable_file(&self, path: &Path) -> Result<Self::Writer> {
+        let mut w = try!(self.inner.open_writable_file(path));
+        let mut salt = [0u8; SALT_SIZE];
+        fill_random(&mut salt);
+        try!(error::from_io_result(w.write_all(&salt)));
+        Ok(EncryptedWriter {
+            inner: w,
+            cipher: self.cipher(),
+            salt: salt,
+            block_idx: 0,
+            buf: Vec::with_capacity(BLOCK_SIZE),
+        })
+    }
+
+    fn open_appendable_file(&self, path: &Path) -> Result<Self::Writer> {
+        let existing = try!(self.inner.size_of(path));
+        if existing == 0 {
+            return self.open_writable_file(path);
+        }
+
+        let raw = try!(self.inner.open_random_access_file(path));
+        let mut salt = [0u8; SALT_SIZE];
+        try!(raw.read_at(0, &mut salt));
+        // Every already-sealed block carries its own nonce derived from `block_idx`, so resuming
+        // after a restart just means counting how many blocks are already on disk and carrying on
+        // from there — blocks never need to be rewritten or merged.
+        let (_, block_idx) = try!(scan_blocks(&*raw));
+
+        let w = try!(self.inner.open_appendable_file(path));
+        Ok(EncryptedWriter {
+            inner: w,
+            cipher: self.cipher(),
+            salt: salt,
+            block_idx: block_idx,
+            buf: Vec::with_capacity(BLOCK_SIZE),
+        })
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool> {
+        self.inner.exists(path)
+    }
+
+    fn children(&self, path: &Path) -> Result<Vec<String>> {
+        self.inner.children(path)
+    }
+
+    fn size_of(&self, path: &Path) -> Result<usize> {
+        let stored = try!(self.inner.size_of(path));
+        if stored <= SALT_SIZE {
+            return Ok(0);
+        }
+        let raw = try!(self.inner.open_random_access_file(path));
+        let (plain, _) = try!(scan_blocks(&*raw));
+        Ok(plain)
+    }
+
+    fn delete(&self, path: &Path) -> Result<()> {
+        self.inner.delete(path)
+    }
+
+    fn mkdir(&self, path: &Path) -> Result<()> {
+        self.inner.mkdir(path)
+    }
+
+    fn rmdir(&self, path: &Path) -> Result<()> {
+        self.inner.rmdir(path)
+    }
+
+    fn rename(&self, old: &Path, new: &Path) -> Result<()> {
+        self.inner.rename(old, new)
+    }
+
+    fn lock(&self, path: &Path) -> Result<Self::FileLock> {
+        self.inner.lock(path)
+    }
+
+    fn unlock(&self, l: Self::FileLock) {
+        self.inner.unlock(l)
+    }
+
+    fn new_logger(&self, path: &Path) -> Result<Logger> {
+        self.inner.new_logger(path)
+    }
+
+    fn micros(&self) -> u64 {
+        self.inner.micros()
+    }
+
+    fn sleep_for(&self, micros: u32) {
+        self.inner.sleep_for(micros)
+    }
+}
+
+/// Reads an encrypted file sequentially, block by block, handing back plaintext.
+pub struct EncryptedSequentialReader<R: Read> {
+    inner: R,
+    cipher: ChaCha20Poly1305,
+    salt: [u8; SALT_SIZE],
+    block_idx: u64,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read> EncryptedSequentialReader<R> {
+    fn fill_buf(&mut self) -> Result<bool> {
+        let mut hdr = [0u8; BLOCK_HEADER_SIZE];
+        match self.inner.read_exact(&mut hdr) {
+            Ok(()) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+            Err(e) => return error::from_io_result::<bool>(Err(e)),
+        }
+        let len = le_u32(&hdr[..4]) as usize;
+        let tag = &hdr[4..];
+        let mut ciphertext = vec![0u8; len];
+        try!(error::from_io_result(self.inner.read_exact(&mut ciphertext)));
+
+        let mut sealed = ciphertext;
+        sealed.extend_from_slice(tag);
+        let nonce = derive_nonce(&self.salt, self.block_idx);
+        self.block_idx += 1;
+
+        match self.cipher.decrypt(&nonce, sealed.as_ref()) {
+            Ok(plain) => {
+                self.buf = plain;
+                self.pos = 0;
+                Ok(true)
+            }
+            Err(_) => crypto_err("EncryptedEnv: authentication failed while decrypting block"),
+        }
+    }
+}
+
+impl<R: Read> Read for EncryptedSequentialReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.buf.len() {
+            match self.fill_buf() {
+                Ok(true) => {}
+                Ok(false) => return Ok(0),
+                Err(_) => return Err(io::Error::new(io::ErrorKind::Other, "decryption failed")),
+            }
+        }
+        let n = ::std::cmp::min(buf.len(), self.buf.len() - self.pos);
+        buf[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Serves positioned reads over an encrypted file by decrypting only the blocks that cover the
+/// requested range.
+pub struct EncryptedRandomAccess {
+    inner: Box<RandomAccess>,
+    cipher: ChaCha20Poly1305,
+}
+
+impl EncryptedRandomAccess {
+    fn decrypt_block(&self, salt: &[u8; SALT_SIZE], block_idx: u64, stored_off: usize)
+                      -> Result<Vec<u8>> {
+        let mut hdr = [0u8; BLOCK_HEADER_SIZE];
+        let n = try!(self.inner.read_at(stored_off, &mut hdr));
+        if n < BLOCK_HEADER_SIZE {
+            return Ok(Vec::new());
+        }
+        let len = le_u32(&hdr[..4]) as usize;
+        let tag = &hdr[4..];
+        let mut sealed = vec![0u8; len];
+        try!(self.inner.read_at(stored_off + BLOCK_HEADER_SIZE, &mut sealed));
+        sealed.extend_from_slice(tag);
+
+        let nonce = derive_nonce(salt, block_idx);
+        match self.cipher.decrypt(&nonce, sealed.as_ref()) {
+            Ok(plain) => Ok(plain),
+            Err(_) => crypto_err("EncryptedEnv: authentication failed while decrypting block"),
+        }
+    }
+}
+
+impl RandomAccess for EncryptedRandomAccess {
+    fn read_at(&self, off: usize, dst: &mut [u8]) -> Result<usize> {
+        if dst.is_empty() {
+            return Ok(0);
+        }
+        let mut salt = [0u8; SALT_SIZE];
+        try!(self.inner.read_at(0, &mut salt));
+
+        // Blocks are variable-length (a mid-stream flush() seals a short block), so we can't jump
+        // straight to the block covering `off` by dividing by BLOCK_SIZE — walk from the start,
+        // reading each header to learn its real length, and only decrypt the blocks that actually
+        // overlap the requested range.
+        let mut stored_off = SALT_SIZE;
+        let mut plain_off = 0usize;
+        let mut block_idx = 0u64;
+        let mut copied = 0usize;
+
+        loop {
+            let mut hdr = [0u8; BLOCK_HEADER_SIZE];
+            let n = try!(self.inner.read_at(stored_off, &mut hdr));
+            if n < BLOCK_HEADER_SIZE {
+                break;
+            }
+            let len = le_u32(&hdr[..4]) as usize;
+            let block_end = plain_off + len;
+
+            let want = off + copied;
+            if want < block_end {
+                let plain = try!(self.decrypt_block(&salt, block_idx, stored_off));
+                let in_block = want - plain_off;
+                if in_block >= plain.len() {
+                    break;
+                }
+                let n_copy = ::std::cmp::min(dst.len() - copied, plain.len() - in_block);
+                dst[copied..copied + n_copy].copy_from_slice(&plain[in_block..in_block + n_copy]);
+                copied += n_copy;
+            }
+
+            if copied >= dst.len() {
+                break;
+            }
+            stored_off += BLOCK_HEADER_SIZE + len;
+            plain_off = block_end;
+            block_idx += 1;
+        }
+        Ok(copied)
+    }
+}
+
+/// Buffers plaintext writes and seals a full block every `BLOCK_SIZE` bytes. `flush` seals
+/// whatever is currently buffered into a (possibly short) block so acknowledged writes are
+/// actually durable on the inner `Env`, matching the `Write::flush` contract the WAL and MANIFEST
+/// writers rely on; any still-unsealed bytes are sealed as a final block on drop.
+pub struct EncryptedWriter<W: Write> {
+    inner: W,
+    cipher: ChaCha20Poly1305,
+    salt: [u8; SALT_SIZE],
+    block_idx: u64,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> EncryptedWriter<W> {
+    fn seal_block(&mut self, plain: &[u8]) -> Result<()> {
+        let nonce = derive_nonce(&self.salt, self.block_idx);
+        self.block_idx += 1;
+        let sealed = match self.cipher.encrypt(&nonce, plain) {
+            Ok(s) => s,
+            Err(_) => return crypto_err("EncryptedEnv: failed to seal block"),
+        };
+        let (ciphertext, tag) = sealed.split_at(sealed.len() - 16);
+
+        let mut hdr = [0u8; BLOCK_HEADER_SIZE];
+        hdr[..4].copy_from_slice(&(ciphertext.len() as u32).to_le_bytes());
+        hdr[4..].copy_from_slice(tag);
+
+        try!(error::from_io_result(self.inner.write_all(&hdr)));
+        try!(error::from_io_result(self.inner.write_all(ciphertext)));
+        Ok(())
+    }
+
+    fn flush_full_blocks(&mut self) -> Result<()> {
+        while self.buf.len() >= BLOCK_SIZE {
+            let block: Vec<u8> = self.buf.drain(..BLOCK_SIZE).collect();
+            try!(self.seal_block(&block));
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for EncryptedWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        if self.flush_full_blocks().is_err() {
+            return Err(io::Error::new(io::ErrorKind::Other, "EncryptedEnv: failed to seal block"));
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.buf.is_empty() {
+            let pending = ::std::mem::replace(&mut self.buf, Vec::new());
+            if self.seal_block(&pending).is_err() {
+                return Err(io::Error::new(io::ErrorKind::Other,
+                                           "EncryptedEnv: failed to seal block"));
+            }
+        }
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Drop for EncryptedWriter<W> {
+    fn drop(&mut self) {
+        if !self.buf.is_empty() {
+            let rest = ::std::mem::replace(&mut self.buf, Vec::new());
+            let _ = self.seal_block(&rest);
+        }
+        let _ = self.inner.flush();
+    }
+}
+
+fn le_u32(b: &[u8]) -> u32 {
+    (b[0] as u32) | (b[1] as u32) << 8 | (b[2] as u32) << 16 | (b[3] as u32) << 24
+}
+
+fn fill_random(buf: &mut [u8]) {
+    use rand::RngCore;
+    rand::thread_rng().fill_bytes(buf);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use env::Env;
+    use mem_env::MemEnv;
+
+    fn test_env() -> EncryptedEnv<MemEnv> {
+        EncryptedEnv::new(MemEnv::new(), [7u8; KEY_SIZE])
+    }
+
+    #[test]
+    fn test_roundtrip_within_one_block() {
+        let env = test_env();
+        let path = Path::new("/db/000001.sst");
+
+        env.open_writable_file(path).unwrap().write_all(b"hello world").unwrap();
+
+        let mut got = Vec::new();
+        env.open_sequential_file(path).unwrap().read_to_end(&mut got).unwrap();
+        assert_eq!(got, b"hello world");
+    }
+
+    #[test]
+    fn test_roundtrip_spans_multiple_blocks() {
+        let env = test_env();
+        let path = Path::new("/db/000002.sst");
+        let data: Vec<u8> = (0..(BLOCK_SIZE * 2 + 37)).map(|i| (i % 251) as u8).collect();
+
+        env.open_writable_file(path).unwrap().write_all(&data).unwrap();
+
+        assert_eq!(env.size_of(path).unwrap(), data.len());
+
+        let mut got = Vec::new();
+        env.open_sequential_file(path).unwrap().read_to_end(&mut got).unwrap();
+        assert_eq!(got, data);
+
+        let raw = env.open_random_access_file(path).unwrap();
+        let mut mid = vec![0u8; 10];
+        let n = raw.read_at(BLOCK_SIZE - 5, &mut mid).unwrap();
+        assert_eq!(n, 10);
+        assert_eq!(mid, data[BLOCK_SIZE - 5..BLOCK_SIZE + 5]);
+    }
+
+    #[test]
+    fn test_flush_makes_partial_block_durable() {
+        let env = test_env();
+        let path = Path::new("/db/LOG");
+
+        {
+            let mut w = env.open_writable_file(path).unwrap();
+            w.write_all(b"partial record").unwrap();
+            w.flush().unwrap();
+            // No further writes and no drop yet: the flushed bytes must already be on the
+            // inner Env, not just buffered in `w`.
+            let mut got = Vec::new();
+            env.open_sequential_file(path).unwrap().read_to_end(&mut got).unwrap();
+            assert_eq!(got, b"partial record");
+        }
+    }
+
+    #[test]
+    fn test_random_access_after_mid_stream_flush() {
+        let env = test_env();
+        let path = Path::new("/db/000004.sst");
+
+        let mut expected = b"short record".to_vec();
+        let more: Vec<u8> = (0..(BLOCK_SIZE + 50)).map(|i| (i % 251) as u8).collect();
+        expected.extend_from_slice(&more);
+
+        {
+            let mut w = env.open_writable_file(path).unwrap();
+            w.write_all(b"short record").unwrap();
+            w.flush().unwrap();
+            w.write_all(&more).unwrap();
+            w.flush().unwrap();
+        }
+
+        assert_eq!(env.size_of(path).unwrap(), expected.len());
+
+        let raw = env.open_random_access_file(path).unwrap();
+        let mut got = vec![0u8; 20];
+        let off = expected.len() - 20;
+        let n = raw.read_at(off, &mut got).unwrap();
+        assert_eq!(n, 20);
+        assert_eq!(got, expected[off..]);
+
+        // Also exercise an offset that lands inside the first, short block.
+        let mut head = vec![0u8; 5];
+        let n = raw.read_at(3, &mut head).unwrap();
+        assert_eq!(n, 5);
+        assert_eq!(head, expected[3..8]);
+    }
+
+    #[test]
+    fn test_append_resumes_after_restart() {
+        let env = test_env();
+        let path = Path::new("/db/MANIFEST-000001");
+
+        {
+            let mut w = env.open_writable_file(path).unwrap();
+            w.write_all(b"first record").unwrap();
+        }
+        {
+            let mut w = env.open_appendable_file(path).unwrap();
+            w.write_all(b"second record").unwrap();
+        }
+
+        let mut got = Vec::new();
+        env.open_sequential_file(path).unwrap().read_to_end(&mut got).unwrap();
+        assert_eq!(got, b"first recordsecond record");
+    }
+
+    #[test]
+    fn test_tampered_ciphertext_fails_to_decrypt() {
+        let env = test_env();
+        let path = Path::new("/db/000003.sst");
+        env.open_writable_file(path).unwrap().write_all(b"sensitive data").unwrap();
+
+        // Read back the raw (encrypted) bytes through the inner Env, flip one ciphertext byte
+        // past the per-file salt and the first block's header, and write the corrupted bytes back.
+        let mut raw = Vec::new();
+        env.inner.open_sequential_file(path).unwrap().read_to_end(&mut raw).unwrap();
+        raw[SALT_SIZE + BLOCK_HEADER_SIZE + 2] ^= 0xff;
+        env.inner.open_writable_file(path).unwrap().write_all(&raw).unwrap();
+
+        let mut got = Vec::new();
+        let result = env.open_sequential_file(path).unwrap().read_to_end(&mut got);
+        assert!(result.is_err());
+    }
+}
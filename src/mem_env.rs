@@ -0,0 +1,305 @@
+//! `MemEnv` is a fully in-process implementation of `Env`, backing every file with a shared
+//! byte buffer instead of the filesystem. It lets tests and ephemeral caches get the behavior of
+//! a real `Env` without touching disk.
+
+use env::{Env, Logger, RandomAccess};
+use error::{self, Result};
+
+use std::collections::HashSet;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+type FileMap = Arc<Mutex<HashMap<PathBuf, Arc<Mutex<Vec<u8>>>>>>;
+
+/// `MemEnv` stores every file as an in-memory byte buffer shared between readers and writers of
+/// the same path, so it behaves like a real `Env` without any disk I/O.
+pub struct MemEnv {
+    files: FileMap,
+    dirs: Mutex<HashSet<PathBuf>>,
+    locks: Mutex<HashSet<PathBuf>>,
+}
+
+impl MemEnv {
+    pub fn new() -> MemEnv {
+        MemEnv {
+            files: Arc::new(Mutex::new(HashMap::new())),
+            dirs: Mutex::new(HashSet::new()),
+            locks: Mutex::new(HashSet::new()),
+        }
+    }
+
+    fn get_file(&self, path: &Path) -> Option<Arc<Mutex<Vec<u8>>>> {
+        let files = self.files.lock().unwrap();
+        files.get(path).cloned()
+    }
+
+    fn get_or_create_file(&self, path: &Path, truncate: bool) -> Arc<Mutex<Vec<u8>>> {
+        let mut files = self.files.lock().unwrap();
+        if truncate {
+            let buf = Arc::new(Mutex::new(Vec::new()));
+            files.insert(path.to_path_buf(), buf.clone());
+            return buf;
+        }
+        files.entry(path.to_path_buf())
+            .or_insert_with(|| Arc::new(Mutex::new(Vec::new())))
+            .clone()
+    }
+}
+
+impl Env for MemEnv {
+    type SequentialReader = MemSequentialReader;
+    type Writer = MemWriter;
+    type FileLock = PathBuf;
+
+    fn open_sequential_file(&self, path: &Path) -> Result<Self::SequentialReader> {
+        match self.get_file(path) {
+            Some(buf) => Ok(MemSequentialReader { buf: buf, pos: 0 }),
+            None => error::from_io_result(Err(not_found(path))),
+        }
+    }
+
+    fn open_random_access_file(&self, path: &Path) -> Result<Box<RandomAccess>> {
+        match self.get_file(path) {
+            Some(buf) => Ok(Box::new(MemRandomAccess { buf: buf })),
+            None => error::from_io_result(Err(not_found(path))),
+        }
+    }
+
+    fn open_writable_file(&self, path: &Path) -> Result<Self::Writer> {
+        Ok(MemWriter { buf: self.get_or_create_file(path, true) })
+    }
+
+    fn open_appendable_file(&self, path: &Path) -> Result<Self::Writer> {
+        Ok(MemWriter { buf: self.get_or_create_file(path, false) })
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool> {
+        let files = self.files.lock().unwrap();
+        Ok(files.contains_key(path))
+    }
+
+    fn children(&self, path: &Path) -> Result<Vec<String>> {
+        let basename = |p: &PathBuf| {
+            if p.parent() == Some(path) {
+                p.file_name().map(|n| n.to_string_lossy().into_owned())
+            } else {
+                None
+            }
+        };
+
+        let mut names: Vec<String> = {
+            let files = self.files.lock().unwrap();
+            files.keys().filter_map(&basename).collect()
+        };
+        {
+            let dirs = self.dirs.lock().unwrap();
+            names.extend(dirs.iter().filter_map(&basename));
+        }
+        names.sort();
+        names.dedup();
+        Ok(names)
+    }
+
+    fn size_of(&self, path: &Path) -> Result<usize> {
+        match self.get_file(path) {
+            Some(buf) => Ok(buf.lock().unwrap().len()),
+            None => error::from_io_result(Err(not_found(path))),
+        }
+    }
+
+    fn delete(&self, path: &Path) -> Result<()> {
+        let mut files = self.files.lock().unwrap();
+        match files.remove(path) {
+            Some(_) => Ok(()),
+            None => error::from_io_result(Err(not_found(path))),
+        }
+    }
+
+    fn mkdir(&self, path: &Path) -> Result<()> {
+        self.dirs.lock().unwrap().insert(path.to_path_buf());
+        Ok(())
+    }
+
+    fn rmdir(&self, path: &Path) -> Result<()> {
+        self.dirs.lock().unwrap().remove(path);
+        Ok(())
+    }
+
+    fn rename(&self, old: &Path, new: &Path) -> Result<()> {
+        let mut files = self.files.lock().unwrap();
+        match files.remove(old) {
+            Some(buf) => {
+                files.insert(new.to_path_buf(), buf);
+                Ok(())
+            }
+            None => error::from_io_result(Err(not_found(old))),
+        }
+    }
+
+    fn lock(&self, path: &Path) -> Result<Self::FileLock> {
+        let mut locks = self.locks.lock().unwrap();
+        if locks.contains(path) {
+            return error::from_io_result(Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                                                              "lock already held")));
+        }
+        locks.insert(path.to_path_buf());
+        Ok(path.to_path_buf())
+    }
+
+    fn unlock(&self, l: Self::FileLock) {
+        self.locks.lock().unwrap().remove(&l);
+    }
+
+    fn new_logger(&self, path: &Path) -> Result<Logger> {
+        let w = try!(self.open_appendable_file(path));
+        Ok(Logger::new(Box::new(w), Box::new(|| 0)))
+    }
+
+    fn micros(&self) -> u64 {
+        0
+    }
+
+    fn sleep_for(&self, _micros: u32) {}
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("{}: no such file in MemEnv", path.display()))
+}
+
+/// Reads a `MemEnv` file sequentially from its current position.
+pub struct MemSequentialReader {
+    buf: Arc<Mutex<Vec<u8>>>,
+    pos: usize,
+}
+
+impl Read for MemSequentialReader {
+    fn read(&mut self, dst: &mut [u8]) -> io::Result<usize> {
+        let buf = self.buf.lock().unwrap();
+        let n = ::std::cmp::min(dst.len(), buf.len().saturating_sub(self.pos));
+        dst[..n].copy_from_slice(&buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Serves positioned reads directly out of a `MemEnv` file's backing buffer.
+pub struct MemRandomAccess {
+    buf: Arc<Mutex<Vec<u8>>>,
+}
+
+impl RandomAccess for MemRandomAccess {
+    fn read_at(&self, off: usize, dst: &mut [u8]) -> Result<usize> {
+        let buf = self.buf.lock().unwrap();
+        if off >= buf.len() {
+            return Ok(0);
+        }
+        let n = ::std::cmp::min(dst.len(), buf.len() - off);
+        dst[..n].copy_from_slice(&buf[off..off + n]);
+        Ok(n)
+    }
+}
+
+/// Appends writes to a `MemEnv` file's backing buffer.
+pub struct MemWriter {
+    buf: Arc<Mutex<Vec<u8>>>,
+}
+
+impl Write for MemWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.lock().unwrap().extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_read_roundtrip() {
+        let env = MemEnv::new();
+        let path = Path::new("/a/b");
+
+        env.open_writable_file(path).unwrap().write_all(b"hello world").unwrap();
+
+        let mut got = Vec::new();
+        env.open_sequential_file(path).unwrap().read_to_end(&mut got).unwrap();
+        assert_eq!(got, b"hello world");
+        assert_eq!(env.size_of(path).unwrap(), 11);
+    }
+
+    #[test]
+    fn test_open_writable_file_truncates() {
+        let env = MemEnv::new();
+        let path = Path::new("/a/b");
+        env.open_writable_file(path).unwrap().write_all(b"first version, much longer").unwrap();
+        env.open_writable_file(path).unwrap().write_all(b"short").unwrap();
+
+        let mut got = Vec::new();
+        env.open_sequential_file(path).unwrap().read_to_end(&mut got).unwrap();
+        assert_eq!(got, b"short");
+    }
+
+    #[test]
+    fn test_open_appendable_file_appends() {
+        let env = MemEnv::new();
+        let path = Path::new("/a/b");
+        env.open_appendable_file(path).unwrap().write_all(b"one ").unwrap();
+        env.open_appendable_file(path).unwrap().write_all(b"two").unwrap();
+
+        let mut got = Vec::new();
+        env.open_sequential_file(path).unwrap().read_to_end(&mut got).unwrap();
+        assert_eq!(got, b"one two");
+    }
+
+    #[test]
+    fn test_random_access() {
+        let env = MemEnv::new();
+        let path = Path::new("/a/b");
+        env.open_writable_file(path).unwrap().write_all(b"0123456789").unwrap();
+
+        let r = env.open_random_access_file(path).unwrap();
+        let mut buf = [0u8; 4];
+        let n = r.read_at(3, &mut buf).unwrap();
+        assert_eq!(n, 4);
+        assert_eq!(&buf, b"3456");
+    }
+
+    #[test]
+    fn test_children_lists_files_and_directories() {
+        let env = MemEnv::new();
+        env.open_writable_file(Path::new("/a/x")).unwrap();
+        env.open_writable_file(Path::new("/a/y")).unwrap();
+        env.mkdir(Path::new("/a/sub")).unwrap();
+
+        let children = env.children(Path::new("/a")).unwrap();
+        assert_eq!(children, vec!["sub".to_string(), "x".to_string(), "y".to_string()]);
+    }
+
+    #[test]
+    fn test_delete_and_exists() {
+        let env = MemEnv::new();
+        env.open_writable_file(Path::new("/a/x")).unwrap();
+        assert!(env.exists(Path::new("/a/x")).unwrap());
+
+        env.delete(Path::new("/a/x")).unwrap();
+        assert!(!env.exists(Path::new("/a/x")).unwrap());
+        assert!(env.delete(Path::new("/a/x")).is_err());
+    }
+
+    #[test]
+    fn test_lock_unlock() {
+        let env = MemEnv::new();
+        let path = Path::new("/a/LOCK");
+        let lock = env.lock(path).unwrap();
+        assert!(env.lock(path).is_err());
+        env.unlock(lock);
+        assert!(env.lock(path).is_ok());
+    }
+}